@@ -1,4 +1,3 @@
-use crate::rewriter::AsciiCompatibleEncoding;
 use encoding_rs::Encoding;
 use std::ptr;
 use std::sync::atomic::{AtomicPtr, Ordering};
@@ -9,6 +8,21 @@ use std::sync::Arc;
 /// This is, for instance, used to adapt the charset dynamically in a [`crate::HtmlRewriter`] if it
 /// encounters a `meta` tag that specifies the charset (that behavior is dependent on
 /// [`crate::Settings::adjust_charset_on_meta_tag`]).
+///
+/// Unlike the old `AsciiCompatibleEncoding`-gated version of this type, [`Self::new`] accepts any
+/// `&'static Encoding`, including non-ASCII-compatible ones such as UTF-16LE/BE: a document may be
+/// legitimately declared in one of these up front, before any tokenizing has relied on its bytes
+/// being ASCII-aligned, and [`crate::rewritable_units::TextDecoder`] already decodes such an
+/// encoding correctly via its streaming slow path.
+///
+/// [`Self::set`] is different: it switches the encoding of a rewrite that may already be under
+/// way, and the rewriter's tokenizer scans raw document bytes for markup (`<`, `>`, attribute
+/// quotes, ...) assuming an ASCII-aligned byte stream laid down by the *original* encoding. Until
+/// a decode-only, re-encode view for the tokenizer exists, switching to a non-ASCII-compatible
+/// encoding mid-rewrite would desync that scan, so [`Self::set`] debug-asserts the new encoding is
+/// ASCII-compatible at the one chokepoint every caller — including
+/// [`crate::rewritable_units::TextDecoder`]'s BOM sniffing and any future meta-charset adjuster —
+/// goes through.
 // Pub only for integration tests
 #[derive(Clone)]
 pub struct SharedEncoding {
@@ -17,8 +31,7 @@ pub struct SharedEncoding {
 
 impl SharedEncoding {
     #[must_use]
-    pub fn new(encoding: AsciiCompatibleEncoding) -> Self {
-        let encoding: &'static Encoding = encoding.into();
+    pub fn new(encoding: &'static Encoding) -> Self {
         Self {
             // `cast_mut` is safe, because `*const T` and `*mut T` are effectively the same thing in Rust,
             // and `*mut T` doesn't have any aliasing requirements from just existing.
@@ -34,8 +47,12 @@ impl SharedEncoding {
         unsafe { &*encoding }
     }
 
-    pub fn set(&self, encoding: AsciiCompatibleEncoding) {
-        let encoding: &'static Encoding = encoding.into();
+    pub fn set(&self, encoding: &'static Encoding) {
+        debug_assert!(
+            encoding.is_ascii_compatible(),
+            "SharedEncoding::set may only switch to an ASCII-compatible encoding until a decode-only, \
+             re-encode view for the tokenizer exists"
+        );
         self.encoding
             .store(ptr::from_ref(encoding).cast_mut(), Ordering::Relaxed);
     }
@@ -44,7 +61,6 @@ impl SharedEncoding {
 #[cfg(test)]
 mod tests {
     use crate::base::SharedEncoding;
-    use crate::AsciiCompatibleEncoding;
     use encoding_rs::Encoding;
 
     /// This serves as a map from integer to [`Encoding`], which allows more efficient
@@ -94,13 +110,17 @@ mod tests {
 
     #[test]
     fn test_encoding_round_trip() {
-        let shared_encoding = SharedEncoding::new(AsciiCompatibleEncoding::utf_8());
+        let shared_encoding = SharedEncoding::new(encoding_rs::UTF_8);
 
-        for &encoding in ALL_ENCODINGS {
-            if let Some(ascii_compat_encoding) = AsciiCompatibleEncoding::new(encoding) {
-                shared_encoding.set(ascii_compat_encoding);
-                assert_eq!(shared_encoding.get(), encoding);
-            }
+        for &encoding in ALL_ENCODINGS.iter().filter(|e| e.is_ascii_compatible()) {
+            shared_encoding.set(encoding);
+            assert_eq!(shared_encoding.get(), encoding);
         }
     }
+
+    #[test]
+    #[should_panic(expected = "ASCII-compatible")]
+    fn test_set_rejects_non_ascii_compatible_encoding() {
+        SharedEncoding::new(encoding_rs::UTF_8).set(encoding_rs::UTF_16LE);
+    }
 }
@@ -1,31 +1,88 @@
 use crate::base::SharedEncoding;
 use crate::rewriter::RewritingError;
-use encoding_rs::{CoderResult, Decoder, Encoding, UTF_8};
+use encoding_rs::{CoderResult, Decoder, Encoding, UTF_16BE, UTF_16LE, UTF_8};
+
+/// The longest BOM we sniff for (the UTF-8 BOM, `EF BB BF`) in bytes.
+const MAX_BOM_LEN: usize = 3;
+
+/// Outcome of inspecting the buffered document-start prefix for a BOM.
+enum BomSniffOutcome {
+    /// Not enough bytes are buffered yet to decide, and the stream hasn't ended.
+    Incomplete,
+    /// No recognized BOM at the start of the document.
+    NoBom,
+    /// A BOM of the given length was found; the document should be reinterpreted in this encoding.
+    Found(usize, &'static Encoding),
+}
+
+/// Checks `buf` (the bytes seen so far at the very start of the document) against the BOMs the
+/// WHATWG Encoding Standard gives priority over any declared or meta-tag charset. `stream_ending`
+/// forces a decision even if `buf` is an ambiguous, too-short prefix of a longer BOM.
+fn sniff_bom(buf: &[u8], stream_ending: bool) -> BomSniffOutcome {
+    const CANDIDATES: &[(&[u8], &Encoding)] = &[
+        (&[0xEF, 0xBB, 0xBF], UTF_8),
+        (&[0xFF, 0xFE], UTF_16LE),
+        (&[0xFE, 0xFF], UTF_16BE),
+    ];
+
+    for &(bom, encoding) in CANDIDATES {
+        if buf.starts_with(bom) {
+            return BomSniffOutcome::Found(bom.len(), encoding);
+        }
+        if !stream_ending && bom.starts_with(buf) {
+            return BomSniffOutcome::Incomplete;
+        }
+    }
+    BomSniffOutcome::NoBom
+}
 
 pub(crate) struct TextDecoder {
     encoding: SharedEncoding,
     pending_text_streaming_decoder: Option<Decoder>,
     text_buffer: String,
+    /// `Some` while still buffering the document-start prefix to sniff a leading BOM; becomes
+    /// `None` for the rest of the document's lifetime as soon as a decision is made. It is `None`
+    /// from the start when BOM sniffing is disabled.
+    bom_sniff_buffer: Option<Vec<u8>>,
+    /// When set, a malformed byte sequence aborts rewriting with [`RewritingError::MalformedEncoding`]
+    /// instead of being silently substituted with U+FFFD.
+    reject_malformed_sequences: bool,
 }
 
+/// Default size, in bytes, of [`TextDecoder`]'s scratch decode buffer; see
+/// [`crate::Settings::decode_buffer_size`].
+pub(crate) const DEFAULT_DECODE_BUFFER_SIZE: usize = 1024;
+
+/// The smallest usable decode buffer: enough room for `decode_to_str` to always make progress
+/// even on a malformed or multi-byte-heavy input, rather than looping forever on `OutputFull`
+/// with nothing written. [`crate::Settings::decode_buffer_size`] is clamped to this.
+const MIN_DECODE_BUFFER_SIZE: usize = 32;
+
 impl TextDecoder {
     #[inline]
     #[must_use]
-    pub fn new(encoding: SharedEncoding) -> Self {
+    pub fn new(
+        encoding: SharedEncoding,
+        sniff_bom: bool,
+        reject_malformed_sequences: bool,
+        decode_buffer_size: usize,
+    ) -> Self {
+        let decode_buffer_size = decode_buffer_size.max(MIN_DECODE_BUFFER_SIZE);
         Self {
             encoding,
             pending_text_streaming_decoder: None,
-            // TODO make adjustable
-            text_buffer: String::from_utf8(vec![0u8; 1024]).unwrap(),
+            text_buffer: String::from_utf8(vec![0u8; decode_buffer_size]).unwrap(),
+            bom_sniff_buffer: sniff_bom.then(Vec::new),
+            reject_malformed_sequences,
         }
     }
 
     #[inline]
     pub fn flush_pending(
         &mut self,
-        output_handler: &mut dyn FnMut(&str, bool, &'static Encoding) -> Result<(), RewritingError>,
+        output_handler: &mut dyn FnMut(&str, bool, &'static Encoding, bool) -> Result<(), RewritingError>,
     ) -> Result<(), RewritingError> {
-        if self.pending_text_streaming_decoder.is_some() {
+        if self.pending_text_streaming_decoder.is_some() || self.bom_sniff_buffer.is_some() {
             self.feed_text(&[], true, output_handler)?;
         }
         Ok(())
@@ -36,7 +93,47 @@ impl TextDecoder {
         &mut self,
         mut raw_input: &[u8],
         last_in_text_node: bool,
-        output_handler: &mut dyn FnMut(&str, bool, &'static Encoding) -> Result<(), RewritingError>,
+        output_handler: &mut dyn FnMut(&str, bool, &'static Encoding, bool) -> Result<(), RewritingError>,
+    ) -> Result<(), RewritingError> {
+        if let Some(buffer) = &mut self.bom_sniff_buffer {
+            let take = (MAX_BOM_LEN - buffer.len()).min(raw_input.len());
+            buffer.extend_from_slice(&raw_input[..take]);
+            raw_input = &raw_input[take..];
+
+            // The sniff window only ever holds up to `MAX_BOM_LEN` bytes, so splicing the
+            // leftover back onto the rest of this call's input is cheap; doing so lets the fast
+            // path and `last_in_text_node` decision below run once over the *whole* input,
+            // instead of leaving a spurious streaming decoder behind for the rest of the node.
+            let mut leftover = match sniff_bom(buffer, buffer.len() == MAX_BOM_LEN || last_in_text_node) {
+                BomSniffOutcome::Incomplete => return Ok(()),
+                BomSniffOutcome::NoBom => self.bom_sniff_buffer.take().unwrap(),
+                // Switching encoding here would desync the tokenizer, which assumes an
+                // ASCII-aligned byte stream; until a decode-only, re-encode view for the
+                // tokenizer exists, a non-ASCII-compatible BOM is left unhonored and
+                // untouched, as if no BOM had been found at all.
+                BomSniffOutcome::Found(_, encoding) if !encoding.is_ascii_compatible() => {
+                    self.bom_sniff_buffer.take().unwrap()
+                }
+                BomSniffOutcome::Found(bom_len, encoding) => {
+                    self.encoding.set(encoding);
+                    let mut leftover = self.bom_sniff_buffer.take().unwrap();
+                    leftover.drain(..bom_len);
+                    leftover
+                }
+            };
+
+            leftover.extend_from_slice(raw_input);
+            return self.feed_text_inner(&leftover, last_in_text_node, output_handler);
+        }
+
+        self.feed_text_inner(raw_input, last_in_text_node, output_handler)
+    }
+
+    fn feed_text_inner(
+        &mut self,
+        mut raw_input: &[u8],
+        last_in_text_node: bool,
+        output_handler: &mut dyn FnMut(&str, bool, &'static Encoding, bool) -> Result<(), RewritingError>,
     ) -> Result<(), RewritingError> {
         let encoding = self.encoding.get();
 
@@ -44,7 +141,9 @@ impl TextDecoder {
             raw_input = rest;
             let really_last = last_in_text_node && rest.is_empty();
 
-            (output_handler)(utf8_text, really_last, encoding)?;
+            // the fast path only ever matches a valid UTF-8/ASCII prefix, so it never replaces
+            // malformed sequences
+            (output_handler)(utf8_text, really_last, encoding, false)?;
 
             if really_last {
                 debug_assert!(self.pending_text_streaming_decoder.is_none());
@@ -58,9 +157,13 @@ impl TextDecoder {
 
         loop {
             let buffer = self.text_buffer.as_mut_str();
-            let (status, read, written, ..) =
+            let (status, read, written, had_errors) =
                 decoder.decode_to_str(raw_input, buffer, last_in_text_node);
 
+            if had_errors && self.reject_malformed_sequences {
+                return Err(RewritingError::MalformedEncoding(encoding.name()));
+            }
+
             let finished_decoding = status == CoderResult::InputEmpty;
 
             if written > 0 || last_in_text_node {
@@ -73,6 +176,7 @@ impl TextDecoder {
                     buffer.get(..written).unwrap_or_default(),
                     really_last,
                     encoding,
+                    had_errors,
                 )?;
             }
 
@@ -102,17 +206,20 @@ impl TextDecoder {
 
         let text_or_len = if encoding == UTF_8 {
             std::str::from_utf8(raw_input).map_err(|err| err.valid_up_to())
-        } else {
-            debug_assert!(encoding.is_ascii_compatible());
+        } else if encoding.is_ascii_compatible() {
             Err(Encoding::ascii_valid_up_to(raw_input))
+        } else {
+            // Byte offsets of ASCII-incompatible encodings (e.g. UTF-16) don't line up with the
+            // decoded `str`, so the fast path can't skip the streaming decoder for them at all.
+            return None;
         };
 
         match text_or_len {
             Ok(utf8_text) => Some((utf8_text, &[][..])),
             Err(valid_up_to) => {
-                // The slow path buffers 1KB, and even though this shouldn't matter,
-                // it is an observable behavior, and it makes bugs worse for text handlers
-                // that assume they'll get only a single chunk.
+                // The slow path buffers up to `self.text_buffer.len()` bytes, and even though this
+                // shouldn't matter, it is an observable behavior, and it makes bugs worse for text
+                // handlers that assume they'll get only a single chunk.
                 if valid_up_to != raw_input.len() && valid_up_to < self.text_buffer.len() {
                     return None;
                 }
@@ -123,3 +230,153 @@ impl TextDecoder {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::SharedEncoding;
+
+    #[test]
+    fn test_sniff_bom_transitions() {
+        assert!(matches!(sniff_bom(&[], false), BomSniffOutcome::Incomplete));
+        assert!(matches!(sniff_bom(&[0xEF], false), BomSniffOutcome::Incomplete));
+        assert!(matches!(sniff_bom(&[0xEF, 0xBB], false), BomSniffOutcome::Incomplete));
+        assert!(matches!(
+            sniff_bom(&[0xEF, 0xBB, 0xBF], false),
+            BomSniffOutcome::Found(3, _)
+        ));
+        assert!(matches!(sniff_bom(&[0xFF, 0xFE], false), BomSniffOutcome::Found(2, _)));
+        assert!(matches!(sniff_bom(&[0xFE, 0xFF], false), BomSniffOutcome::Found(2, _)));
+        assert!(matches!(sniff_bom(b"Hi", false), BomSniffOutcome::NoBom));
+        // An ambiguous lone prefix byte is never completed into a BOM once the stream ends.
+        assert!(matches!(sniff_bom(&[0xEF], true), BomSniffOutcome::NoBom));
+    }
+
+    #[test]
+    fn test_short_first_text_node_emits_exactly_one_last_chunk() {
+        // "Hi" is a perfectly ordinary first text node that happens to be shorter than the
+        // 3-byte BOM-sniff window; it must not be split into a spurious empty trailing chunk,
+        // nor leave a decoder dangling that would disable the fast path for the rest of the node.
+        let mut decoder =
+            TextDecoder::new(SharedEncoding::new(UTF_8), true, false, DEFAULT_DECODE_BUFFER_SIZE);
+        let mut chunks = Vec::new();
+
+        decoder
+            .feed_text(b"Hi", true, &mut |text, really_last, _encoding, had_errors| {
+                chunks.push((text.to_owned(), really_last, had_errors));
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(chunks, vec![("Hi".to_owned(), true, false)]);
+        assert!(decoder.pending_text_streaming_decoder.is_none());
+    }
+
+    #[test]
+    fn test_first_text_node_longer_than_bom_window_emits_exactly_one_last_chunk() {
+        // Unlike the "Hi" case above, this input overflows the 3-byte BOM-sniff window on the
+        // very first `feed_text` call; the leftover sniffed bytes must still be rejoined with the
+        // rest of the input so the fast path sees (and fully consumes) the whole thing in one go,
+        // rather than leaving a streaming decoder dangling that forces the remainder through the
+        // slow path and splits it into a second chunk.
+        let mut decoder =
+            TextDecoder::new(SharedEncoding::new(UTF_8), true, false, DEFAULT_DECODE_BUFFER_SIZE);
+        let mut chunks = Vec::new();
+        let text = b"Hello, World! This is longer than the BOM-sniff window.";
+
+        decoder
+            .feed_text(text, true, &mut |text, really_last, _encoding, had_errors| {
+                chunks.push((text.to_owned(), really_last, had_errors));
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(
+            chunks,
+            vec![(String::from_utf8(text.to_vec()).unwrap(), true, false)]
+        );
+        assert!(decoder.pending_text_streaming_decoder.is_none());
+    }
+
+    #[test]
+    fn test_malformed_sequence_is_replaced_by_default() {
+        // A byte that never occurs in UTF-8 (`\xFF`) forces the streaming decoder's slow path, which
+        // substitutes U+FFFD unless `reject_malformed_sequences` is set.
+        let mut decoder =
+            TextDecoder::new(SharedEncoding::new(UTF_8), false, false, DEFAULT_DECODE_BUFFER_SIZE);
+        let mut had_errors = false;
+
+        decoder
+            .feed_text(b"ok\xFF", true, &mut |_text, _really_last, _encoding, errors| {
+                had_errors |= errors;
+                Ok(())
+            })
+            .unwrap();
+
+        assert!(had_errors);
+    }
+
+    #[test]
+    fn test_malformed_sequence_is_rejected_in_strict_mode() {
+        let mut decoder =
+            TextDecoder::new(SharedEncoding::new(UTF_8), false, true, DEFAULT_DECODE_BUFFER_SIZE);
+
+        let err = decoder
+            .feed_text(b"ok\xFF", true, &mut |_text, _really_last, _encoding, _errors| Ok(()))
+            .unwrap_err();
+
+        assert!(matches!(err, RewritingError::MalformedEncoding("UTF-8")));
+    }
+
+    #[test]
+    fn test_decode_buffer_size_is_clamped_to_a_minimum() {
+        // A 0-byte (or otherwise too-small) buffer must not make `decode_to_str` loop forever on
+        // `OutputFull` with nothing written. The leading `\xE9` forces the streaming decoder's
+        // slow path (it's not ASCII), so this actually exercises `self.text_buffer`.
+        let mut decoder =
+            TextDecoder::new(SharedEncoding::new(encoding_rs::WINDOWS_1252), false, false, 0);
+        let mut chunks = Vec::new();
+
+        decoder
+            .feed_text(b"\xE9llo", true, &mut |text, _really_last, _encoding, _errors| {
+                chunks.push(text.to_owned());
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(chunks.concat(), "\u{e9}llo");
+    }
+
+    #[test]
+    fn test_small_decode_buffer_splits_output_into_more_chunks() {
+        // UTF-16LE is never eligible for the UTF-8/ASCII fast path, so it always takes the
+        // streaming decoder's slow path, which chunks its output on `self.text_buffer.len()`.
+        let text = "hello world, this is more than one small buffer's worth of text";
+        let utf16le: Vec<u8> = text.encode_utf16().flat_map(u16::to_le_bytes).collect();
+
+        let mut small =
+            TextDecoder::new(SharedEncoding::new(UTF_16LE), false, false, MIN_DECODE_BUFFER_SIZE);
+        let mut large =
+            TextDecoder::new(SharedEncoding::new(UTF_16LE), false, false, DEFAULT_DECODE_BUFFER_SIZE);
+
+        let mut small_chunks = Vec::new();
+        small
+            .feed_text(&utf16le, true, &mut |t, _last, _enc, _err| {
+                small_chunks.push(t.to_owned());
+                Ok(())
+            })
+            .unwrap();
+
+        let mut large_chunks = Vec::new();
+        large
+            .feed_text(&utf16le, true, &mut |t, _last, _enc, _err| {
+                large_chunks.push(t.to_owned());
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(small_chunks.concat(), text);
+        assert_eq!(large_chunks.concat(), text);
+        assert!(small_chunks.len() > large_chunks.len());
+    }
+}
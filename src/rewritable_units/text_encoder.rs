@@ -0,0 +1,145 @@
+use crate::rewriter::RewritingError;
+use encoding_rs::{Encoder, EncoderResult, Encoding, UTF_8};
+use std::io::Write;
+
+/// Re-encodes the UTF-8 the rewriter's `output_handler` produces into a legacy output charset.
+///
+/// This implements the WHATWG "HTML" encoder behavior: a scalar value the target encoding can't
+/// represent is replaced with the ASCII bytes of a decimal numeric character reference
+/// (e.g. `&#9731;`) instead of failing the whole rewrite. UTF-8 output (the default) never needs
+/// this stage; [`TextEncoder::is_noop`] lets callers skip it entirely in that case.
+pub(crate) struct TextEncoder {
+    encoder: Option<Encoder>,
+    byte_buffer: Vec<u8>,
+}
+
+impl TextEncoder {
+    #[inline]
+    #[must_use]
+    pub fn new(encoding: &'static Encoding) -> Self {
+        Self {
+            encoder: (encoding != UTF_8).then(|| encoding.new_encoder()),
+            byte_buffer: vec![0u8; 1024],
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_noop(&self) -> bool {
+        self.encoder.is_none()
+    }
+
+    /// Encodes `utf8_text`, calling `output_handler` with each chunk of re-encoded bytes as the
+    /// internal scratch buffer fills up.
+    pub fn encode(
+        &mut self,
+        mut utf8_text: &str,
+        output_handler: &mut dyn FnMut(&[u8]) -> Result<(), RewritingError>,
+    ) -> Result<(), RewritingError> {
+        let Some(encoder) = &mut self.encoder else {
+            return output_handler(utf8_text.as_bytes());
+        };
+
+        while !utf8_text.is_empty() {
+            let (result, read, written) =
+                encoder.encode_from_utf8_without_replacement(utf8_text, &mut self.byte_buffer, false);
+
+            if written > 0 {
+                output_handler(&self.byte_buffer[..written])?;
+            }
+            utf8_text = &utf8_text[read..];
+
+            if let EncoderResult::Unmappable(scalar_value) = result {
+                let mut ncr_buf = [0u8; NCR_BUF_LEN];
+                output_handler(numeric_character_reference(&mut ncr_buf, scalar_value))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes any state the encoder carries between calls (e.g. a stateful legacy encoding's
+    /// shift state), emitting a final chunk if needed. Call once, after the last [`Self::encode`].
+    pub fn flush(
+        &mut self,
+        output_handler: &mut dyn FnMut(&[u8]) -> Result<(), RewritingError>,
+    ) -> Result<(), RewritingError> {
+        let Some(encoder) = &mut self.encoder else {
+            return Ok(());
+        };
+
+        loop {
+            let (result, _read, written) =
+                encoder.encode_from_utf8_without_replacement("", &mut self.byte_buffer, true);
+
+            if written > 0 {
+                output_handler(&self.byte_buffer[..written])?;
+            }
+
+            match result {
+                EncoderResult::InputEmpty => return Ok(()),
+                EncoderResult::OutputFull => continue,
+                EncoderResult::Unmappable(_) => unreachable!("flushing empty input can't be unmappable"),
+            }
+        }
+    }
+}
+
+/// `&#` + up to 9 decimal digits (the largest scalar value, `0x10FFFF`, is 7 digits, plus room to
+/// spare) + `;`.
+const NCR_BUF_LEN: usize = 12;
+
+/// Formats `scalar_value` as the ASCII bytes of a decimal numeric character reference, per the
+/// WHATWG Encoding Standard's "HTML" encode behavior.
+fn numeric_character_reference(buf: &mut [u8; NCR_BUF_LEN], scalar_value: char) -> &[u8] {
+    let mut cursor = &mut buf[..];
+    // Writing a handful of ASCII digits into a fixed-size stack buffer never fails.
+    write!(cursor, "&#{};", scalar_value as u32).unwrap();
+    let written = NCR_BUF_LEN - cursor.len();
+    &buf[..written]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::WINDOWS_1252;
+
+    #[test]
+    fn test_numeric_character_reference_formatting() {
+        let mut buf = [0u8; NCR_BUF_LEN];
+        assert_eq!(numeric_character_reference(&mut buf, '\u{2603}'), b"&#9731;");
+        assert_eq!(numeric_character_reference(&mut buf, '\u{10FFFF}'), b"&#1114111;");
+        assert_eq!(numeric_character_reference(&mut buf, 'a'), b"&#97;");
+    }
+
+    fn encode_all(encoder: &mut TextEncoder, text: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        encoder
+            .encode(text, &mut |bytes| {
+                out.extend_from_slice(bytes);
+                Ok(())
+            })
+            .unwrap();
+        encoder
+            .flush(&mut |bytes| {
+                out.extend_from_slice(bytes);
+                Ok(())
+            })
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_utf8_output_is_a_noop() {
+        let mut encoder = TextEncoder::new(UTF_8);
+        assert!(encoder.is_noop());
+        assert_eq!(encode_all(&mut encoder, "caf\u{e9} \u{2603}"), "caf\u{e9} \u{2603}".as_bytes());
+    }
+
+    #[test]
+    fn test_unmappable_character_falls_back_to_numeric_character_reference() {
+        let mut encoder = TextEncoder::new(WINDOWS_1252);
+        assert!(!encoder.is_noop());
+        // '\u{e9}' (é) is mappable in windows-1252, '\u{2603}' (☃) is not.
+        assert_eq!(encode_all(&mut encoder, "caf\u{e9} \u{2603}!"), b"caf\xe9 &#9731;!");
+    }
+}
@@ -0,0 +1,21 @@
+use std::fmt;
+
+/// Errors that can occur while rewriting a document.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RewritingError {
+    /// A malformed byte sequence was encountered while decoding text in the named encoding, and
+    /// [`crate::Settings::reject_malformed_sequences`] is set. Without that flag, the sequence
+    /// is silently replaced with U+FFFD instead of aborting the rewrite.
+    MalformedEncoding(&'static str),
+}
+
+impl fmt::Display for RewritingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MalformedEncoding(encoding) => write!(f, "malformed `{encoding}` byte sequence"),
+        }
+    }
+}
+
+impl std::error::Error for RewritingError {}
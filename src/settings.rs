@@ -0,0 +1,95 @@
+use crate::base::SharedEncoding;
+use crate::rewritable_units::{TextDecoder, TextEncoder};
+use encoding_rs::{Encoding, UTF_8};
+
+/// Charset-handling knobs for [`crate::HtmlRewriter`].
+///
+/// This only lists the fields read by this crate's text decode/encode pipeline; the
+/// content-handler and output-sink fields live on the same struct.
+#[non_exhaustive]
+pub struct Settings {
+    /// Reinterpret the document in a charset found in a `meta` tag, per the WHATWG Encoding
+    /// Standard's charset-sniffing algorithm. Defaults to `false`.
+    pub adjust_charset_on_meta_tag: bool,
+    /// Honor a leading byte-order mark at document start, overriding the declared or meta-tag
+    /// charset, per the WHATWG Encoding Standard. Only a BOM for an ASCII-compatible encoding
+    /// (currently just the UTF-8 BOM) is actually honored; see
+    /// [`crate::base::SharedEncoding`]. Defaults to `false`.
+    pub sniff_bom: bool,
+    /// The charset the rewriter's output is re-encoded into, via [`crate::rewritable_units::TextEncoder`].
+    /// Unmappable characters are replaced with a numeric character reference, per the WHATWG
+    /// Encoding Standard's "HTML" encode behavior. Defaults to [`UTF_8`], which is a zero-cost
+    /// passthrough of the UTF-8 the rewriter produces internally.
+    pub output_encoding: &'static Encoding,
+    /// Abort rewriting with [`crate::rewriter::RewritingError::MalformedEncoding`] on the first
+    /// malformed input byte sequence, instead of silently substituting U+FFFD. Defaults to
+    /// `false`.
+    pub reject_malformed_sequences: bool,
+    /// Size, in bytes, of [`crate::rewritable_units::TextDecoder`]'s scratch decode buffer.
+    /// Larger buffers reduce the frequency of multi-chunk text emission (fewer `output_handler`
+    /// invocations and fewer partial-chunk splits), at the cost of more memory per rewriter
+    /// instance; smaller buffers trade the reverse. Values below a small internal minimum are
+    /// clamped up rather than accepted as-is. Defaults to 1 KB.
+    pub decode_buffer_size: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            adjust_charset_on_meta_tag: false,
+            sniff_bom: false,
+            output_encoding: UTF_8,
+            reject_malformed_sequences: false,
+            decode_buffer_size: crate::rewritable_units::text_decoder::DEFAULT_DECODE_BUFFER_SIZE,
+        }
+    }
+}
+
+impl Settings {
+    /// Builds the [`TextEncoder`] that [`crate::HtmlRewriter`] re-encodes its output through,
+    /// per [`Self::output_encoding`].
+    #[must_use]
+    pub(crate) fn new_text_encoder(&self) -> TextEncoder {
+        TextEncoder::new(self.output_encoding)
+    }
+
+    /// Builds the [`TextDecoder`] that [`crate::HtmlRewriter`] decodes its input through, for a
+    /// document starting out in `encoding`, per [`Self::sniff_bom`],
+    /// [`Self::reject_malformed_sequences`], and [`Self::decode_buffer_size`].
+    #[must_use]
+    pub(crate) fn new_text_decoder(&self, encoding: SharedEncoding) -> TextDecoder {
+        TextDecoder::new(
+            encoding,
+            self.sniff_bom,
+            self.reject_malformed_sequences,
+            self.decode_buffer_size,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::UTF_8;
+
+    #[test]
+    fn test_default_output_encoding_is_a_noop_encoder() {
+        assert!(Settings::default().new_text_encoder().is_noop());
+    }
+
+    #[test]
+    fn test_new_text_decoder_reads_settings_fields() {
+        let settings = Settings {
+            sniff_bom: true,
+            reject_malformed_sequences: true,
+            ..Settings::default()
+        };
+        let mut decoder = settings.new_text_decoder(SharedEncoding::new(UTF_8));
+
+        let err = decoder
+            .feed_text(b"\xEF\xBB\xBFok\xFF", true, &mut |_text, _last, _encoding, _errors| Ok(()))
+            .unwrap_err();
+
+        assert!(matches!(err, crate::rewriter::RewritingError::MalformedEncoding("UTF-8")));
+    }
+}